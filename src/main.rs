@@ -3,25 +3,58 @@ extern crate clap;
 #[macro_use]
 extern crate lazy_static;
 
-use std::collections::BTreeSet;
 use std::collections::BTreeMap;
 use std::cmp::Ordering;
 use std::collections::BinaryHeap;
 use std::io::{self, Write};
 
-#[derive(PartialEq)]
-enum BoardArea {
-  ROW,
-  COL,
-  REGION,
-  ALL
+// Bitmask with bits 1..=9 set, i.e. every candidate value turned on
+const ALL_NUMS: u16 = ((1u16 << 9) - 1) << 1;
+
+/// Returns the single-bit mask representing `value`
+fn bit(value: u8) -> u16 {
+  1u16 << value
+}
+
+/// Returns the value represented by a single-bit mask
+fn value_of(mask: u16) -> u8 {
+  mask.trailing_zeros() as u8
 }
 
-// Static universe set (values 1 to 9)
+// The 27 units (9 rows, 9 columns, 9 regions) as groups of board indices
 lazy_static! {
-  static ref U: BTreeSet<u8> = {
-    (1u8..10u8).into_iter().collect()
-  };
+  static ref UNITS: Vec<[usize; 9]> = build_units();
+}
+
+/// Builds the list of 27 units (rows, columns, regions) as board indices
+fn build_units() -> Vec<[usize; 9]> {
+  let mut units = Vec::with_capacity(27);
+  for row in 0..9 {
+    let mut unit = [0usize; 9];
+    for (col, slot) in unit.iter_mut().enumerate() {
+      *slot = id(row, col);
+    }
+    units.push(unit);
+  }
+  for col in 0..9 {
+    let mut unit = [0usize; 9];
+    for (row, slot) in unit.iter_mut().enumerate() {
+      *slot = id(row, col);
+    }
+    units.push(unit);
+  }
+  for start in vec![0, 3, 6, 27, 30, 33, 54, 57, 60] {
+    let mut unit = [0usize; 9];
+    let mut k = 0;
+    for row in 0..3 {
+      for col in 0..3 {
+        unit[k] = start + 9 * row + col;
+        k += 1;
+      }
+    }
+    units.push(unit);
+  }
+  units
 }
 
 /// Unflattens a list into an uncompressed game board
@@ -104,230 +137,157 @@ fn id(row: usize, col: usize) -> usize {
   9 * row + col
 }
 
-/// Given a 1D index, return the index of the first element in that row
-fn get_row_start(i: usize) -> usize {
-  (i / 9) * 9
+/// Converts an unflattened board into a candidate grid
+///
+/// Assigned cells become a single-bit mask of their value;
+/// unassigned cells become `ALL_NUMS`.
+fn to_candidates(board: &[u8]) -> [u16; 81] {
+  let mut candidates = [0u16; 81];
+  for i in 0..81 {
+    candidates[i] = if board[i] == 0u8 { ALL_NUMS } else { bit(board[i]) };
+  }
+  candidates
 }
 
-/// Given a 1D index, return the index of the first element in that column
-fn get_col_start(i: usize) -> usize {
-  i % 9
+/// Converts a candidate grid back into an unflattened board
+///
+/// Cells that are not yet narrowed down to a single candidate
+/// are left unassigned (value 0).
+fn to_board(candidates: &[u16; 81]) -> Vec<u8> {
+  candidates.iter().map(|&mask| {
+    if mask.count_ones() == 1 { value_of(mask) } else { 0u8 }
+  }).collect()
 }
 
-/// Given a 1D index, return the index of the top-left element in that region
-fn get_region_start(i: usize) -> usize {
-  // {0, 27, 54}  + {0, 3, 6}
-  ((i / 27) * 27) + (((i % 9) / 3) * 3)
+/// Returns true if every cell has exactly one candidate left
+fn is_solved(candidates: &[u16; 81]) -> bool {
+  candidates.iter().all(|&mask| mask.count_ones() == 1)
 }
 
-/// Return the set of used values in the scope of the given cell
-fn get_used(board: &[u8], i: usize, area: BoardArea) -> BTreeSet<u8> {
-  let mut used: BTreeSet<u8> = BTreeSet::new();
-  // Accumulate along row
-  if area == BoardArea::ROW || area == BoardArea::ALL {
-    let row_start = get_row_start(i);
-    for j in row_start .. row_start + 9 {
-      let value = board[j];
-      if value != 0u8 {
-        used.insert(value);
-      }
-    }
-  }
-  // Accumulate along column
-  if area == BoardArea::COL || area == BoardArea::ALL {
-    let col_start = get_col_start(i);
-    for j in 0..9 {
-      let value = board[9 * j + col_start];
-      if value != 0u8 {
-        used.insert(value);
-      }
-    }
-  }
-  // Accumulate in region
-  if area == BoardArea::REGION || area == BoardArea::ALL {
-    let region_start = get_region_start(i);
-    for j in 0..3 {
-      for k in 0..3 {
-        let value = board[9 * j + region_start + k];
-        if value != 0u8 {
-          used.insert(value);
+/// Returns true if no unit holds the same assigned value twice
+///
+/// A branch's guessed value can propagate into two cells that only share
+/// one axis (say, a column) each independently settling on the same
+/// value via a *different* axis (their own rows), since elimination never
+/// revisits a cell once it has a single candidate left. `is_solved` alone
+/// can't tell that apart from an actual solution, so this must be checked
+/// before trusting a "solved" board.
+fn is_valid(candidates: &[u16; 81]) -> bool {
+  UNITS.iter().all(|unit| {
+    let mut used = 0u16;
+    for &i in unit.iter() {
+      if candidates[i].count_ones() == 1 {
+        if used & candidates[i] != 0 {
+          return false;
         }
+        used |= candidates[i];
       }
     }
-  }
-  used
-}
-
-/// Return the set of missing values in the scope of the given cell
-fn get_missing(board: &[u8], area: BoardArea, start: usize) -> BTreeSet<u8> {
-  let used: BTreeSet<u8> = get_used(&board, start, area);
-  U.difference(&used).cloned().collect()
+    true
+  })
 }
 
-/// Assign values to unassigned cells in the board
-///
-/// Multiple rounds of solve may need to be called to solve the entire puzzle
-/// Returns the number of assignments made in this round
-fn solve(board: &mut [u8], verbose: bool) -> usize {
-  let mut assigned: usize = 0;
-
-  // Find used/free values for all cells
-  for row in 0..9 {
-    for col in 0..9 {
-      let used = get_used(&board, id(row, col), BoardArea::ALL);
-      let free: BTreeSet<u8> = U.difference(&used).cloned().collect();
-      if verbose {
-        println!("At scope of ({},{}) [{}], used: {:?}, free: {:?}", row, col, id(row, col), used, free);
-      }
-      if board[id(row, col)] == 0u8 && free.len() == 1 {
-        board[id(row, col)] = *free.iter().next().unwrap();
-        assigned += 1;
-        if verbose {
-          println!("Assign [{}] to {}", id(row, col), board[id(row, col)]);
-        }
-      }
+/// Repeatedly applies `f` to `x` until the result stops changing
+fn fixed_point<T: Eq + Clone>(x: T, f: impl Fn(T) -> T) -> T {
+  let mut cur = x;
+  loop {
+    let next = f(cur.clone());
+    if next == cur {
+      return next;
     }
+    cur = next;
   }
+}
 
-  if verbose {
-    print_board(&board);
-  }
-
-  // Cross-reference missing values in board areas with free values in their cells
-
-  // Row
-  for row in 0..9 {
-    let missing = get_missing(&board, BoardArea::ROW, id(row, 0));
-    if verbose {
-      println!("At row {}, missing: {:?}", row, missing);
-    }
-    // Go through all columns and record positions that can fulfill the missing value
-    let mut candidates: BTreeMap<u8, Vec<usize>> = BTreeMap::new();
-    for col in 0..9 {
-      if board[id(row, col)] == 0u8 { // unassigned cells only
-        for value in &get_missing(&board, BoardArea::ALL, id(row, col)) {
-          if missing.contains(&value) {
-            candidates.entry(*value).or_default().push(id(row, col));
-          }
-        }
-      }
-    }
-    // If any missing value can only be fulfilled by one position, assign it
-    for (value, positions) in &candidates {
-      if verbose && positions.len() > 0 {
-        println!("Value {} can be fulfilled by positions: {:?}", value, positions);
-      }
-      if positions.len() == 1 {
-        board[positions[0]] = *value;
-        assigned += 1;
-        if verbose {
-          println!("Assign [{}] to {}", positions[0], *value);
-        }
-      }
+/// Clears the bits of already-assigned values from every other cell in a
+/// unit (a naked single falls out once this leaves a cell with one bit)
+fn eliminate_unit(candidates: &mut [u16; 81], unit: &[usize; 9]) -> bool {
+  let mut used = 0u16;
+  for &i in unit.iter() {
+    if candidates[i].count_ones() == 1 {
+      used |= candidates[i];
     }
   }
-
-  if verbose {
-    print_board(&board);
+  let mut changed = false;
+  for &i in unit.iter() {
+    if candidates[i].count_ones() > 1 {
+      let before = candidates[i];
+      candidates[i] &= !used;
+      changed |= candidates[i] != before;
+    }
   }
+  changed
+}
 
-  // Column
-  for col in 0..9 {
-    let missing = get_missing(&board, BoardArea::COL, id(0, col));
-    if verbose {
-      println!("At column {}, missing: {:?}", col, missing);
-    }
-    // Go through all rows and record positions that can fulfill the missing value
-    let mut candidates: BTreeMap<u8, Vec<usize>> = BTreeMap::new();
-    for row in 0..9 {
-      if board[id(row, col)] == 0u8 { // unassigned cells only
-        for value in &get_missing(&board, BoardArea::ALL, id(row, col)) {
-          if missing.contains(&value) {
-            candidates.entry(*value).or_default().push(id(row, col));
-          }
-        }
-      }
+/// Runs `eliminate_unit` across all 27 units until none of them has
+/// anything left to clear
+///
+/// Note this only reaches a fixed point over cells that still have more
+/// than one candidate: once a cell is down to one, nothing ever revisits
+/// it, so a branch guess that forces two cells sharing just one axis to
+/// the same value (each via a *different* axis) won't be caught here —
+/// see `is_valid`, which callers must check before trusting a "solved"
+/// board.
+fn eliminate(candidates: &mut [u16; 81]) {
+  loop {
+    let mut changed = false;
+    for unit in UNITS.iter() {
+      changed |= eliminate_unit(candidates, unit);
     }
-    // If any missing value can only be fulfilled by one position, assign it
-    for (value, positions) in &candidates {
-      if verbose && positions.len() > 0 {
-        println!("Value {} can be fulfilled by positions: {:?}", value, positions);
-      }
-      if positions.len() == 1 {
-        board[positions[0]] = *value;
-        assigned += 1;
-        if verbose {
-          println!("Assign [{}] to {}", positions[0], *value);
-        }
-      }
+    if !changed {
+      break;
     }
   }
+}
 
-  if verbose {
-    print_board(&board);
-  }
-
-  // Region
-  for start in vec![0, 3, 6, 27, 30, 33, 54, 57, 60] {
-    let missing = get_missing(&board, BoardArea::REGION, start);
-    if verbose {
-      println!("At region {}, missing: {:?}", start, missing);
-    }
-    // Go through all cells of the region and record positions that can fulfill the missing value
-    let mut candidates: BTreeMap<u8, Vec<usize>> = BTreeMap::new();
-    for row in 0..3 {
-      for col in 0..3 {
-        let pos = start + 9 * row + col;
-        if board[pos] == 0u8 { // unassigned cells only
-          for value in &get_missing(&board, BoardArea::ALL, pos) {
-            if missing.contains(&value) {
-              candidates.entry(*value).or_default().push(pos);
-            }
-          }
+/// Finds a value whose candidate bit survives in exactly one cell of some
+/// unit (a hidden single), if any remain
+fn find_hidden_single(candidates: &[u16; 81]) -> Option<(usize, u8)> {
+  for unit in UNITS.iter() {
+    for value in 1u8..=9 {
+      let b = bit(value);
+      let mut count = 0usize;
+      let mut pos = 0usize;
+      for &i in unit.iter() {
+        if candidates[i] & b != 0 {
+          count += 1;
+          pos = i;
         }
       }
-    }
-    // If any missing value can only be fulfilled by one position, assign it
-    for (value, positions) in &candidates {
-      if verbose && positions.len() > 0 {
-        println!("Value {} can be fulfilled by positions: {:?}", value, positions);
-      }
-      if positions.len() == 1 {
-        board[positions[0]] = *value;
-        assigned += 1;
-        if verbose {
-          println!("Assign [{}] to {}", positions[0], *value);
-        }
+      if count == 1 && candidates[pos].count_ones() > 1 {
+        return Some((pos, value));
       }
     }
   }
-
-  if verbose {
-    println!("Made {} assignments", assigned);
-    print_board(&board);
-  }
-  
-  assigned
+  None
 }
 
-/// Returns true if the puzzle is solved
-fn is_solved(board: &[u8]) -> bool {
-  for value in board.iter() {
-    if *value == 0u8 {
-      return false;
-    }
+/// Applies one round of candidate propagation
+///
+/// Elimination always runs to a complete fixed point first, and at most
+/// one hidden single is assigned per round, so that committing it can be
+/// fully propagated (via the next round's elimination) before any other
+/// unit gets a chance to assign a conflicting peer based on stale state.
+fn solve(candidates: [u16; 81], verbose: bool) -> [u16; 81] {
+  let mut next = candidates;
+  eliminate(&mut next);
+  if let Some((pos, value)) = find_hidden_single(&next) {
+    next[pos] = bit(value);
+  }
+  if verbose {
+    print_board(&to_board(&next));
   }
-  true
+  next
 }
 
 /// Represents a branch for the dynamic programming solver
 #[derive(Clone, Eq, PartialEq)]
 struct Branch {
-  _pos: usize,    // 1D index in the unflattened board
-  _val: u8,       // value to branch on
-  _cut: usize,    // number of possible values to branch on
-  _depth: usize,  // branch depth
-  _board: Vec<u8>
+  _pos: usize,         // 1D index in the unflattened board
+  _val: u8,            // value to branch on
+  _cut: usize,         // number of possible values to branch on
+  _depth: usize,       // branch depth
+  _candidates: [u16; 81]
 }
 // 1. _cut, ascending
 // 2. _depth, descending
@@ -348,22 +308,22 @@ impl PartialOrd for Branch {
 }
 
 /// Add branches to the heap
-fn add_heap(heap: &mut BinaryHeap<Branch>, board: Vec<u8>, depth: usize) {
-  for row in 0..9 {
-    for col in 0..9 {
-      if board[id(row, col)] != 0u8 {
-        continue
-      }
-      let used = get_used(&board, id(row, col), BoardArea::ALL);
-      let free: BTreeSet<u8> = U.difference(&used).cloned().collect();
-      for v in &free {
+fn add_heap(heap: &mut BinaryHeap<Branch>, candidates: [u16; 81], depth: usize) {
+  for i in 0..81 {
+    let mask = candidates[i];
+    let cut = mask.count_ones() as usize;
+    if cut <= 1 {
+      continue
+    }
+    for value in 1u8..=9 {
+      if mask & bit(value) != 0 {
         heap.push(
           Branch {
-            _pos: id(row, col),
-            _val: *v,
-            _cut: free.len(),
+            _pos: i,
+            _val: value,
+            _cut: cut,
             _depth: depth,
-            _board: board.clone()
+            _candidates: candidates
           }
         );
       }
@@ -411,7 +371,7 @@ fn main() {
   // Generate the seed, flattened list, and unflattened board
   let seed: String;
   let list: Vec<u8>;
-  let mut board: Vec<u8>;
+  let board: Vec<u8>;
 
   if args.is_present("seed") {
     seed = args.value_of("seed").unwrap().to_string();
@@ -457,37 +417,29 @@ fn main() {
   // Print the initial board state
   print_board(&board);
 
-  let mut round: usize = 0;
-  let mut assigned: usize = 1;
-  while assigned > 0 && ! is_solved(&board) {
-    round += 1;
-    if verbose {
-      println!("Round {}", round);
-    }
-    assigned = solve(&mut board, verbose);
-  }
+  let candidates = fixed_point(to_candidates(&board), |c| solve(c, verbose));
 
-  if is_solved(&board) {
+  if is_solved(&candidates) && is_valid(&candidates) {
     println!("Finished solver, puzzle is solved.");
-    print_board(&board);
+    print_board(&to_board(&candidates));
     std::process::exit(0);
   }
-  
+
   // Dynamic programming
   // Branch on cells with minimal number of free values
   if verbose {
     println!("Finished initial solver");
-    print_board(&board);
+    print_board(&to_board(&candidates));
     println!("Starting dynamic programming");
   }
 
   // Populate priority queue of cells to branch on
   let mut pq = BinaryHeap::new();
-  add_heap(&mut pq, board, 0);
+  add_heap(&mut pq, candidates, 0);
 
   let mut heartbeat: usize = 0;
   const INTERVAL: usize = 50;
-  while let Some(Branch {_pos, _val, _cut, _depth, mut _board}) = pq.pop() {
+  while let Some(Branch {_pos, _val, _cut, _depth, mut _candidates}) = pq.pop() {
     if verbose {
       println!("Branch depth {}: set [{}] to {} (of {})", _depth, _pos, _val, _cut);
     }
@@ -502,20 +454,17 @@ fn main() {
         io::stdout().flush().unwrap();
       }
     }
-    _board[_pos] = _val;
-    assigned = 1;
-    while assigned > 0 && ! is_solved(&_board) {
-      assigned = solve(&mut _board, verbosity > 1);
-    }
-    if is_solved(&_board) {
+    _candidates[_pos] = bit(_val);
+    _candidates = fixed_point(_candidates, |c| solve(c, verbosity > 1));
+    if is_solved(&_candidates) && is_valid(&_candidates) {
       if heartbeat >= INTERVAL {
         println!();
       }
       println!("Finished solver, puzzle is solved.");
-      print_board(&_board);
+      print_board(&to_board(&_candidates));
       std::process::exit(0);
     }
-    add_heap(&mut pq, _board, _depth + 1);
+    add_heap(&mut pq, _candidates, _depth + 1);
   }
   if heartbeat >= INTERVAL {
     println!();